@@ -0,0 +1,46 @@
+// Dials a `KvsServer` and issues `Set`/`Get`/`Rm` requests using the same
+// length-prefixed wire protocol the server speaks.
+use crate::net::{read_frame, write_frame};
+use crate::{KvStore, Operation, Response};
+use crate::err::{KvError, Result};
+use std::net::{TcpStream, ToSocketAddrs};
+
+pub struct KvsClient {
+    stream: TcpStream,
+}
+
+impl KvsClient {
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        Ok(Self { stream: TcpStream::connect(addr)? })
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        match self.request(Operation::Set(key, value))? {
+            Response::Value(_) => Ok(()),
+            Response::NotFound => Ok(()),
+            Response::Err(msg) => Err(KvError::Remote(msg)),
+        }
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.request(Operation::Get(key))? {
+            Response::Value(value) => Ok(Some(value)),
+            Response::NotFound => Ok(None),
+            Response::Err(msg) => Err(KvError::Remote(msg)),
+        }
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        match self.request(Operation::Rm(key))? {
+            Response::Value(_) => Ok(()),
+            Response::NotFound => Err(KvError::KeyNotFound),
+            Response::Err(msg) => Err(KvError::Remote(msg)),
+        }
+    }
+
+    fn request(&mut self, op: Operation) -> Result<Response> {
+        write_frame(&mut self.stream, &KvStore::encode_operation(&op))?;
+        let payload = read_frame(&mut self.stream)?;
+        KvStore::decode_response(&payload)
+    }
+}
@@ -10,7 +10,11 @@ pub enum KvError {
     #[fail(display = "unknown command")]
     UnKnownCommand,
     #[fail(display = "serde err: {}", _0)]
-    Serde(#[cause] serde_json::Error)
+    Serde(#[cause] serde_json::Error),
+    #[fail(display = "unsupported log format version: {}", _0)]
+    UnsupportedLogVersion(u8),
+    #[fail(display = "server error: {}", _0)]
+    Remote(String),
 }
 
 impl From<io::Error> for KvError {
@@ -1,32 +1,98 @@
-#[macro_use] extern crate failure;
-
 use std::path::{PathBuf, Path};
 use serde::{Serialize, Deserialize};
-use serde_json::Deserializer;
-use std::collections::HashMap;
+use std::collections::{HashMap, BTreeMap};
 use std::io::prelude::*;
-use std::io::{SeekFrom, BufReader};
+use std::io::{SeekFrom, BufReader, Cursor, ErrorKind};
 use std::fs::{OpenOptions, File, read_dir};
+use std::ops::RangeBounds;
+use std::convert::TryInto;
 pub use err::Result;
 
 pub mod err;
+pub mod server;
+pub mod client;
+mod net;
 
 const SINGLE_LOG_SIZE: usize = 1024*1024; // 1M
 const COMPACT_THRESHOLD: u64 = 1024*1024; // 1M
+// [u32 payload_len][u32 crc32(payload)] prefixes every record on disk.
+const HEADER_LEN: u64 = 8;
+// default zstd level used to compress a segment written by `compact()`
+const ZSTD_COMPACT_LEVEL: i32 = 3;
 static NOT_COMMIT_FILE: &str = "not_commit.dat";
+static SNAPSHOT_FILE: &str = "index.snapshot";
+static SNAPSHOT_TMP_FILE: &str = "index.snapshot.tmp";
+static LOG_EXT: &str = "log";
+static COMPRESSED_LOG_EXT: &str = "zlog";
+// records the on-disk format version for the whole data directory, so `open`
+// can refuse a directory written by a newer build with a clear error instead
+// of misparsing it. Shares its version numbering with `LOG_FORMAT_VERSION`.
+static VERSION_FILE: &str = "version";
 
 pub struct KvStore {
-    readers: HashMap<u64, BufReader<File>>,
+    readers: HashMap<u64, Reader>,
     writer: BufWriter,
-    index: HashMap<String, Pointer>,
+    // a BTreeMap so `scan`/`keys` can walk the live key space in sorted
+    // order without an extra sort pass.
+    index: BTreeMap<String, Pointer>,
     fid: u64,
     file_path: PathBuf,
     rubbish: u64,
+    sync_policy: SyncPolicy,
+}
+
+/// Controls how aggressively `KvStore` fsyncs the active log. `set`/`remove`
+/// always flush the `BufWriter` so reads observe their own writes, but
+/// flushing only pushes bytes to the OS page cache; without an explicit
+/// `fsync` a power loss can still drop acknowledged writes.
+#[derive(Clone, Copy)]
+pub enum SyncPolicy {
+    /// Never call `fsync`; rely entirely on the OS to flush the page cache.
+    Never,
+    /// Call `fsync` after every `set`/`remove`. Safest, slowest.
+    EveryWrite,
+    /// Call `fsync` once at least `n` bytes have been appended since the
+    /// last sync.
+    BytesPerSync(u64),
+}
+
+impl Default for SyncPolicy {
+    fn default() -> Self {
+        SyncPolicy::BytesPerSync(4 * 1024 * 1024) // 4MB
+    }
+}
+
+// A segment is either the active, uncompressed log (appended to directly) or
+// a compacted `.zlog` segment: compaction writes a segment once and it's
+// read many times after, so it's decompressed fully into memory at open
+// time and served out of that buffer for random reads.
+enum Reader {
+    Plain(BufReader<File>),
+    Compressed(Cursor<Vec<u8>>),
+}
+
+impl Read for Reader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Reader::Plain(r) => r.read(buf),
+            Reader::Compressed(r) => r.read(buf),
+        }
+    }
+}
+
+impl Seek for Reader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            Reader::Plain(r) => r.seek(pos),
+            Reader::Compressed(r) => r.seek(pos),
+        }
+    }
 }
 
 struct BufWriter {
     inner: std::io::BufWriter<File>,
     pos: usize,
+    bytes_since_sync: u64,
 }
 
 impl BufWriter {
@@ -34,14 +100,29 @@ impl BufWriter {
         Self {
             inner: std::io::BufWriter::new(inner),
             pos:0,
+            bytes_since_sync: 0,
         }
     }
+
+    fn maybe_sync(&mut self, policy: SyncPolicy) -> std::io::Result<()> {
+        let should_sync = match policy {
+            SyncPolicy::Never => false,
+            SyncPolicy::EveryWrite => true,
+            SyncPolicy::BytesPerSync(n) => self.bytes_since_sync >= n,
+        };
+        if should_sync {
+            self.inner.get_ref().sync_data()?;
+            self.bytes_since_sync = 0;
+        }
+        Ok(())
+    }
 }
 
 impl Write for BufWriter{
     fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
         let n = self.inner.write(data)?;
         self.pos += n;
+        self.bytes_since_sync += n as u64;
         Ok(n)
     }
     fn flush(&mut self) -> std::io::Result<()> {
@@ -49,12 +130,28 @@ impl Write for BufWriter{
     }
 }
 
+#[derive(Serialize, Deserialize)]
 struct Pointer {
     fid: u64,
     start: u64,
     len: u64,
 }
 
+// The on-disk index snapshot written by `compact()`: the recovered index
+// plus the highest fid it covers, so `open` knows which log files are
+// already folded in and only needs to replay the ones after it.
+#[derive(Deserialize)]
+struct Snapshot {
+    last_fid: u64,
+    index: BTreeMap<String, Pointer>,
+}
+
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    last_fid: u64,
+    index: &'a BTreeMap<String, Pointer>,
+}
+
 impl Pointer {
     fn new(fid: u64, start: u64, len: u64) -> Self {
         Self{
@@ -63,61 +160,284 @@ impl Pointer {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug)]
 enum Operation {
     Set(String, String),
     Rm(String),
+    // brackets a run of Set/Rm records written by KvStore::write as one
+    // atomic WriteBatch; the u32 is the number of enclosed records.
+    BatchBegin(u32),
+    BatchEnd,
+    // only ever sent over the wire by KvsClient, never written to a log.
+    Get(String),
+}
+
+// the pre-chunk0-5 on-disk record shape: plain serde_json values with no
+// length/crc framing and no version header. Only `upgrade` ever decodes
+// this; normal replay only ever sees the current binary format.
+#[derive(Deserialize)]
+enum LegacyOperation {
+    Set(String, String),
+    Rm(String),
 }
 
+const OP_SET: u8 = 1;
+const OP_RM: u8 = 2;
+const OP_BATCH_BEGIN: u8 = 3;
+const OP_BATCH_END: u8 = 4;
+const OP_GET: u8 = 5;
+// version byte written at the head of every log file so an old log
+// written by a pre-binary-format build is rejected with a clear error
+// instead of being silently misparsed.
+const LOG_FORMAT_VERSION: u8 = 1;
+
+/// Accumulates `Set`/`Rm` mutations to be applied atomically by
+/// `KvStore::write`: either every op lands, or (if a crash cuts the batch
+/// short) none of them do.
+#[derive(Default)]
+pub struct WriteBatch {
+    ops: Vec<Operation>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self { ops: Vec::new() }
+    }
+
+    pub fn set(&mut self, key: String, value: String) {
+        self.ops.push(Operation::Set(key, value));
+    }
+
+    pub fn remove(&mut self, key: String) {
+        self.ops.push(Operation::Rm(key));
+    }
+}
+
+// the index update a single batched Set/Rm record resolves to, applied only
+// after the whole batch has been written and its BatchEnd marker flushed.
+enum BatchUpdate {
+    Set(String, Pointer),
+    Rm(String, u64),
+}
+
+// a WriteBatch being replayed by `load_data`: the position it started at (to
+// roll back to if it's never closed by a BatchEnd), the record count its
+// `BatchBegin` declared (to check against on `BatchEnd`), and the Set/Rm
+// records seen so far, applied in one pass once the BatchEnd is seen.
+type PendingBatch = (u64, u32, Vec<(Operation, u64, u64)>);
+
+/// The wire response `KvsServer` sends back to a `KvsClient` request: either
+/// the outcome carries a value (a `Get` hit, or a bare acknowledgement for a
+/// successful `Set`/`Rm`), "not found", or an error message.
+enum Response {
+    Value(String),
+    NotFound,
+    Err(String),
+}
+
+const RESP_VALUE: u8 = 1;
+const RESP_NOT_FOUND: u8 = 2;
+const RESP_ERR: u8 = 3;
+
 
 impl KvStore {
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with_config(path, SyncPolicy::default())
+    }
+
+    pub fn open_with_config(path: impl Into<PathBuf>, sync_policy: SyncPolicy) -> Result<Self> {
         let file_path = path.into();
         std::fs::create_dir_all(file_path.clone())?;
+        Self::check_version_manifest(file_path.as_path())?;
         let recover_file = file_path.join(NOT_COMMIT_FILE);
         if std::fs::metadata(recover_file.as_path()).is_ok() {
-            return Self::recover_from_crash(file_path);
+            return Self::recover_from_crash(file_path, sync_policy);
         }
         let mut readers = HashMap::new();
         let file_ids = Self::sort_log(file_path.as_path())?;
-        let mut index = HashMap::new();
+        let (mut index, mut snapshot_fid) = Self::load_snapshot(file_path.as_path())?;
         let mut rubbish = 0;
+
+        // A compacted `.zlog` segment is a full, authoritative dump of the
+        // live set as of its own fid, not an incremental one. A fid newer
+        // than the snapshot's means the snapshot predates that compaction
+        // (missing, or a crash before it was rewritten) -- merging the
+        // segment on top of such a snapshot would keep stale Pointers for
+        // keys the compaction deleted, or Pointers left over from a prior
+        // compaction that reused the same fid in an older build. Safer to
+        // throw the whole snapshot away and let the loop below rebuild the
+        // index from the segment (and everything after it) from scratch.
+        if let Some(&zlog_fid) = file_ids.iter().find(|&&fid| Self::segment_path(file_path.as_path(), fid).1) {
+            if zlog_fid > snapshot_fid {
+                index = BTreeMap::new();
+                snapshot_fid = 0;
+            }
+        }
+
         for &file_id in &file_ids {
-            let mut fd = BufReader::new(
-                OpenOptions::new().read(true).open(
-                    file_path.join(format!("{}.log", file_id))
-                )?
-            );
-            rubbish += Self::load_data(file_id, &mut fd, &mut index)?;
-            readers.insert(file_id, fd);
+            let (seg_path, compressed) = Self::segment_path(file_path.as_path(), file_id);
+            let mut reader = Self::open_reader(&seg_path, compressed)?;
+            // files already folded into the snapshot don't need replaying;
+            // only the ones appended since are applied on top of it. A
+            // compacted `.zlog` segment is replayed the same as a plain one
+            // rather than trusted to the snapshot alone, so a missing or
+            // crash-interrupted snapshot write still leaves the segment's
+            // data recoverable.
+            if file_id > snapshot_fid {
+                match &mut reader {
+                    Reader::Plain(r) => { rubbish += Self::load_data(file_id, r, &mut index)?; },
+                    Reader::Compressed(r) => { rubbish += Self::load_data(file_id, r, &mut index)?; },
+                }
+            }
+            readers.insert(file_id, reader);
         }
 
         let last_id = *file_ids.last().unwrap_or(&0) + 1;
-        let mut writer = Self::new_log_file(last_id, file_path.as_path(), &mut readers)?;
-        Ok(Self{readers, writer, index, fid: last_id, file_path, rubbish})
+        let writer = Self::new_log_file(last_id, file_path.as_path(), &mut readers)?;
+        Self::write_version_manifest(file_path.as_path())?;
+        Ok(Self{readers, writer, index, fid: last_id, file_path, rubbish, sync_policy})
+    }
+
+    // read the directory-level version manifest, if one exists, and refuse to
+    // open a directory stamped with a newer format than this build supports.
+    // A missing manifest means either a brand-new directory or one written
+    // before this manifest existed; `open` lets that through and stamps it on
+    // success, same as an old log file is let through until its own version
+    // header is checked.
+    fn check_version_manifest(path: &Path) -> Result<()> {
+        let manifest_path = path.join(VERSION_FILE);
+        if std::fs::metadata(&manifest_path).is_err() {
+            return Ok(());
+        }
+        let mut version = [0u8; 1];
+        OpenOptions::new().read(true).open(&manifest_path)?.read_exact(&mut version)?;
+        if version[0] > LOG_FORMAT_VERSION {
+            return Err(err::KvError::UnsupportedLogVersion(version[0]));
+        }
+        Ok(())
+    }
+
+    fn write_version_manifest(path: &Path) -> Result<()> {
+        std::fs::write(path.join(VERSION_FILE), [LOG_FORMAT_VERSION])?;
+        Ok(())
+    }
+
+    // `compact()`/`upgrade()` only ever leave `not_commit.dat` behind in the
+    // window between writing the compacted segment and renaming it into its
+    // final `<fid>.zlog` name -- by which point any old segments it makes
+    // obsolete may already be gone, since that rename is the last step. So
+    // recovery just finishes that interrupted commit: give the segment a
+    // fid past anything left on disk (same rule `compact()` uses), rename
+    // `not_commit.dat` into place, write the snapshot that covers it, then
+    // fall through to a normal open so the rest of the directory (the
+    // active log written after the crash, say) replays as usual instead of
+    // re-entering recovery forever.
+    fn recover_from_crash(path: PathBuf, sync_policy: SyncPolicy) -> Result<Self> {
+        let file_ids = Self::sort_log(path.as_path())?;
+        let new_fid = file_ids.iter().max().copied().unwrap_or(0) + 1;
+        let not_commit_file = path.join(NOT_COMMIT_FILE);
+        let commit_file = path.join(format!("{}.{}", new_fid, COMPRESSED_LOG_EXT));
+        std::fs::rename(&not_commit_file, &commit_file)?;
+
+        let mut index = BTreeMap::new();
+        let mut reader = Self::open_reader(&commit_file, true)?;
+        match &mut reader {
+            Reader::Plain(r) => { Self::load_data(new_fid, r, &mut index)?; },
+            Reader::Compressed(r) => { Self::load_data(new_fid, r, &mut index)?; },
+        }
+        Self::write_snapshot(path.as_path(), new_fid, &index)?;
+
+        Self::open_with_config(path, sync_policy)
     }
 
-    fn recover_from_crash(path: PathBuf) -> Result<Self> {
-        let f_path = path.join(NOT_COMMIT_FILE);
-        let mut reader = BufReader::new(OpenOptions::new().read(true).open(f_path)?);
-        let mut index = HashMap::new();
-        let rubbish = Self::load_data(1, &mut reader, &mut index)?;
+    /// Migrate a data directory written by an older, pre-binary-format build
+    /// (plain JSON records, no CRC framing, no version manifest) into the
+    /// current on-disk format: replay every legacy log, then rewrite the live
+    /// key/value set as a single current-format segment via the same
+    /// compaction-style pass `compact()` uses. A no-op if `path` is already
+    /// on the current format.
+    pub fn upgrade(path: impl Into<PathBuf>) -> Result<()> {
+        let file_path = path.into();
+        std::fs::create_dir_all(file_path.clone())?;
+        if std::fs::metadata(file_path.join(VERSION_FILE)).is_ok() {
+            // only ever written once a directory is on the current format.
+            return Ok(());
+        }
+
+        let mut live: HashMap<String, String> = HashMap::new();
+        let file_ids = Self::sort_log(file_path.as_path())?;
+        for &file_id in &file_ids {
+            let (seg_path, compressed) = Self::segment_path(file_path.as_path(), file_id);
+            if compressed || Self::is_current_format(&seg_path)? {
+                let mut reader = Self::open_reader(&seg_path, compressed)?;
+                let mut index = BTreeMap::new();
+                match &mut reader {
+                    Reader::Plain(r) => { Self::load_data(file_id, r, &mut index)?; },
+                    Reader::Compressed(r) => { Self::load_data(file_id, r, &mut index)?; },
+                }
+                for (key, Pointer{start, len, ..}) in index {
+                    reader.seek(SeekFrom::Start(start))?;
+                    let mut payload = vec![0u8; len as usize];
+                    reader.read_exact(&mut payload)?;
+                    if let Operation::Set(_, value) = Self::decode_operation(&payload)? {
+                        live.insert(key, value);
+                    }
+                }
+            } else {
+                Self::load_legacy_log(&seg_path, &mut live)?;
+            }
+        }
+
+        // same fresh-fid-per-compaction rule as `compact()` (see
+        // `open_with_config`): a migration is just a one-off compaction of
+        // the legacy data, so it needs the same guarantee that a crash
+        // between committing the segment and writing its snapshot doesn't
+        // leave `open` unable to tell the segment apart from an older one.
+        let new_fid = file_ids.iter().max().copied().unwrap_or(0) + 1;
+        let index = Self::write_compacted_segment(file_path.as_path(), new_fid, live, &file_ids)?;
         let mut readers = HashMap::new();
-        readers.insert(1, reader);
-        let fid = 2;
-        let mut writer = Self::new_log_file(fid, path.as_path(), &mut readers)?;
-        Ok(Self{readers, writer, file_path: path, fid, index, rubbish})
+        let commit_compact_file = file_path.join(format!("{}.{}", new_fid, COMPRESSED_LOG_EXT));
+        readers.insert(new_fid, Self::open_reader(&commit_compact_file, true)?);
+        Self::new_log_file(new_fid + 1, file_path.as_path(), &mut readers)?;
+        Self::write_snapshot(file_path.as_path(), new_fid, &index)?;
+        Self::write_version_manifest(file_path.as_path())?;
+        Ok(())
+    }
+
+    // a log file starts with `LOG_FORMAT_VERSION`; a legacy, pre-binary-format
+    // log is plain JSON and almost certainly won't start with that byte.
+    fn is_current_format(path: &Path) -> Result<bool> {
+        let mut version = [0u8; 1];
+        OpenOptions::new().read(true).open(path)?.read_exact(&mut version)?;
+        Ok(version[0] == LOG_FORMAT_VERSION)
+    }
+
+    // replay a legacy (pre-chunk0-5) log file: back-to-back serde_json
+    // `LegacyOperation` records with no length/crc framing, applied directly
+    // since the legacy format has no Pointer-style byte offsets worth keeping.
+    fn load_legacy_log(path: &Path, live: &mut HashMap<String, String>) -> Result<()> {
+        let fd = OpenOptions::new().read(true).open(path)?;
+        let stream = serde_json::Deserializer::from_reader(BufReader::new(fd)).into_iter::<LegacyOperation>();
+        for op in stream {
+            match op? {
+                LegacyOperation::Set(key, value) => { live.insert(key, value); },
+                LegacyOperation::Rm(key) => { live.remove(&key); },
+            }
+        }
+        Ok(())
     }
 
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
         let op = Operation::Set(key, value);
         let cur = self.writer.pos;
-        let data = serde_json::to_string(&op)?;
-        let len = self.writer.write(data.as_bytes())?;
+        let payload = Self::encode_operation(&op);
+        Self::write_frame(&mut self.writer, &payload)?;
         self.writer.flush()?;
+        self.writer.maybe_sync(self.sync_policy)?;
         if let Operation::Set(key, _) = op {
-            if let Some(old) = self.index.insert(key, Pointer::new(self.fid, cur as u64, len as u64)) {
-                self.rubbish += old.len;
+            let ptr = Pointer::new(self.fid, cur as u64 + HEADER_LEN, payload.len() as u64);
+            if let Some(old) = self.index.insert(key, ptr) {
+                self.rubbish += HEADER_LEN + old.len;
             }
         }
         if self.rubbish >= COMPACT_THRESHOLD {
@@ -134,8 +454,9 @@ impl KvStore {
         if let Some(&Pointer{fid, start, len}) = self.index.get(&key) {
             if let Some(r) = self.readers.get_mut(&fid) {
                 r.seek(SeekFrom::Start(start))?;
-                let mut t = r.take(len);
-                if let Operation::Set(_, value) = serde_json::from_reader(t)? {
+                let mut payload = vec![0u8; len as usize];
+                r.read_exact(&mut payload)?;
+                if let Operation::Set(_, value) = Self::decode_operation(&payload)? {
                     Ok(Some(value))
                 } else {
                     Err(err::KvError::UnKnownCommand)
@@ -150,28 +471,102 @@ impl KvStore {
 
     pub fn remove(&mut self, key: String) -> Result<()> {
         if let Some(Pointer{len,..}) = self.index.remove(&key) {
-            self.rubbish += len;
+            self.rubbish += HEADER_LEN + len;
             let op = Operation::Rm(key);
-            let s = serde_json::to_string(&op)?;
-            let n = self.writer.write(s.as_bytes())?;
-            self.rubbish += n as u64;
+            let payload = Self::encode_operation(&op);
+            Self::write_frame(&mut self.writer, &payload)?;
+            self.writer.flush()?;
+            self.writer.maybe_sync(self.sync_policy)?;
+            self.rubbish += HEADER_LEN + payload.len() as u64;
             Ok(())
         } else {
             Err(err::KvError::KeyNotFound)
         }
     }
 
+    /// Walk the live keys falling inside `range` in sorted order, resolving
+    /// each one's value through the existing `Pointer`/reader machinery.
+    pub fn scan(&mut self, range: impl RangeBounds<String>) -> impl Iterator<Item = Result<(String, String)>> + '_ {
+        let keys: Vec<String> = self.index.range(range).map(|(k, _)| k.clone()).collect();
+        keys.into_iter().map(move |key| {
+            let value = self.get(key.clone())?.ok_or(err::KvError::KeyNotFound)?;
+            Ok((key, value))
+        })
+    }
+
+    /// A keys-only iterator over the live key space, in sorted order. Never
+    /// touches the log files since it only reads the in-memory index.
+    pub fn keys(&self) -> impl Iterator<Item = String> + '_ {
+        self.index.keys().cloned()
+    }
+
+    /// Write every op in `batch` into the active log as one contiguous
+    /// region framed by `BatchBegin`/`BatchEnd` markers, flushing/syncing
+    /// once, then apply the resulting index updates in a single pass. A
+    /// crash that leaves the batch without its `BatchEnd` marker discards
+    /// the whole batch on the next replay (see `load_data`).
+    pub fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        if batch.ops.is_empty() {
+            return Ok(());
+        }
+
+        let begin = Self::encode_operation(&Operation::BatchBegin(batch.ops.len() as u32));
+        Self::write_frame(&mut self.writer, &begin)?;
+
+        let mut updates = Vec::with_capacity(batch.ops.len());
+        for op in &batch.ops {
+            let cur = self.writer.pos as u64;
+            let payload = Self::encode_operation(op);
+            let payload_len = payload.len() as u64;
+            Self::write_frame(&mut self.writer, &payload)?;
+            match op {
+                Operation::Set(key, _) => updates.push(BatchUpdate::Set(
+                    key.clone(),
+                    Pointer::new(self.fid, cur + HEADER_LEN, payload_len),
+                )),
+                Operation::Rm(key) => updates.push(BatchUpdate::Rm(key.clone(), payload_len)),
+                Operation::BatchBegin(_) | Operation::BatchEnd | Operation::Get(_) => unreachable!("WriteBatch only holds Set/Rm"),
+            }
+        }
+
+        let end = Self::encode_operation(&Operation::BatchEnd);
+        Self::write_frame(&mut self.writer, &end)?;
+        self.writer.flush()?;
+        self.writer.maybe_sync(self.sync_policy)?;
+
+        for update in updates {
+            match update {
+                BatchUpdate::Set(key, ptr) => {
+                    if let Some(old) = self.index.insert(key, ptr) {
+                        self.rubbish += HEADER_LEN + old.len;
+                    }
+                },
+                BatchUpdate::Rm(key, payload_len) => {
+                    if let Some(old) = self.index.remove(&key) {
+                        self.rubbish += HEADER_LEN + old.len;
+                    }
+                    self.rubbish += HEADER_LEN + payload_len;
+                },
+            }
+        }
+
+        if self.rubbish >= COMPACT_THRESHOLD {
+            self.compact()?;
+        } else if self.writer.pos >= SINGLE_LOG_SIZE {
+            self.fid += 1;
+            let w = Self::new_log_file(self.fid, self.file_path.as_path(), &mut self.readers)?;
+            self.writer = w;
+        }
+        Ok(())
+    }
+
     fn compact(&mut self) -> Result<()> {
-        // the easiest way of compacting is to rewrite all the live data to a new log file
-        // but there's a problem, which log we should write to?
-        // if we indefinitely increase the file number, u64 will one day overflow.
-        // so, just remove all the log files and rewrite the live data to 1.log
-        // but if the machine shutdown unexpectedly after we delete all the log file
-        // while we haven't stored the live data on the disk, we'll lose all the data!!
-        // It's unacceptable!!
-        // So before deleting the old log files, we must write the live data to another file
-        // say, not_commit.dat
-        // then we delete old log files, then rename not_commit.dat to 1.log
+        // rewrite all the live data into a single new compacted segment, then
+        // remove every old segment. The segment gets a fresh fid rather than
+        // reusing a fixed one: `open` tells a stale/missing snapshot apart
+        // from a segment it actually covers by comparing fids (see
+        // `open_with_config`), which only works if each compaction produces
+        // a fid newer than the last.
 
         // rebuild the kv in memory
         let mut temp_index = HashMap::new();
@@ -182,52 +577,124 @@ impl KvStore {
             }
         }
 
-        // write the kv data in not_commit.dat
-        let not_commit_compact_file = self.file_path.join(NOT_COMMIT_FILE);
-        let compact_fd = OpenOptions::new().create(true).append(true).open(not_commit_compact_file.clone())?;
-        let mut compact_writer = BufWriter::new(compact_fd);
-        let mut pos = 0;
-        self.index = HashMap::new();
-        for (k,v) in temp_index {
-            let op = Operation::Set(k,v);
-            let s = serde_json::to_string(&op)?;
-            let n = compact_writer.write(s.as_bytes())?;
+        let old_fids: Vec<u64> = self.readers.keys().cloned().collect();
+        let new_fid = self.fid + 1;
+        self.index = Self::write_compacted_segment(self.file_path.as_path(), new_fid, temp_index, &old_fids)?;
+
+        let commit_compact_file = self.file_path.join(format!("{}.{}", new_fid, COMPRESSED_LOG_EXT));
+        self.readers = HashMap::new();
+        self.readers.insert(new_fid, Self::open_reader(&commit_compact_file, true)?);
+        self.fid = new_fid + 1;
+        let writer = Self::new_log_file(self.fid, self.file_path.as_path(), &mut self.readers)?;
+        self.writer = writer;
+        self.rubbish = 0;
+
+        Self::write_snapshot(self.file_path.as_path(), new_fid, &self.index)?;
+        Self::write_version_manifest(self.file_path.as_path())?;
+
+        Ok(())
+    }
+
+    // frame `live` into an in-memory buffer (same framing as the active log),
+    // zstd-compress it, crash-safely commit it as `<fid>.zlog` (write to
+    // not_commit.dat, then rename), and remove `old_fids`'s segments. Shared
+    // by `compact()` and `upgrade()`, which both reduce a directory down to
+    // its live key/value set and rewrite it as a single current-format
+    // segment; the only difference is where that live set came from.
+    fn write_compacted_segment(file_path: &Path, fid: u64, live: HashMap<String, String>, old_fids: &[u64]) -> Result<BTreeMap<String, Pointer>> {
+        let mut buf: Vec<u8> = Vec::new();
+        Self::write_log_header(&mut buf)?;
+        let mut pos = 1u64;
+        let mut index = BTreeMap::new();
+        for (k, v) in live {
+            let op = Operation::Set(k, v);
+            let payload = Self::encode_operation(&op);
+            Self::write_frame(&mut buf, &payload)?;
             if let Operation::Set(key, _) = op {
-                self.index.insert(key, Pointer{fid: 1, start: pos, len: n as u64});
+                index.insert(key, Pointer{fid, start: pos + HEADER_LEN, len: payload.len() as u64});
             }
-            pos += n as u64;
+            pos += HEADER_LEN + payload.len() as u64;
         }
-        compact_writer.flush()?;
+        let compressed = zstd::encode_all(buf.as_slice(), ZSTD_COMPACT_LEVEL)?;
 
-        // delete the old log files
-        for &fid in self.readers.keys() {
-            std::fs::remove_file(self.file_path.join(format!("{}.log", fid)))?;
+        let not_commit_compact_file = file_path.join(NOT_COMMIT_FILE);
+        std::fs::write(&not_commit_compact_file, &compressed)?;
+
+        for &old_fid in old_fids {
+            for ext in [LOG_EXT, COMPRESSED_LOG_EXT] {
+                let _ = std::fs::remove_file(file_path.join(format!("{}.{}", old_fid, ext)));
+            }
         }
 
-        // rename not_commit.dat to 1.log
-        let commit_compact_file = self.file_path.join(format!("{}.log", 1));
-        std::fs::rename(not_commit_compact_file, commit_compact_file.clone())?;
-        let compact_fd = OpenOptions::new().read(true).open(commit_compact_file)?;
-        self.readers = HashMap::new();
-        self.readers.insert(1, BufReader::new(compact_fd));
-        let mut writer = Self::new_log_file(2, self.file_path.as_path(), &mut self.readers)?;
-        self.writer = writer;
-        self.fid = 2;
-        self.rubbish = 0;
+        let commit_compact_file = file_path.join(format!("{}.{}", fid, COMPRESSED_LOG_EXT));
+        std::fs::rename(not_commit_compact_file, commit_compact_file)?;
+
+        Ok(index)
+    }
 
+    // write the index snapshot to a temp file and atomically rename it into
+    // place, the same crash-safe pattern `compact()` uses for not_commit.dat,
+    // so a crash never leaves a half-written snapshot that could desync from
+    // the logs.
+    fn write_snapshot(path: &Path, last_fid: u64, index: &BTreeMap<String, Pointer>) -> Result<()> {
+        let tmp_path = path.join(SNAPSHOT_TMP_FILE);
+        let fd = OpenOptions::new().create(true).write(true).truncate(true).open(&tmp_path)?;
+        serde_json::to_writer(fd, &SnapshotRef{last_fid, index})?;
+        std::fs::rename(tmp_path, path.join(SNAPSHOT_FILE))?;
         Ok(())
     }
 
-    fn new_log_file(fid: u64, path: &Path, readers: &mut HashMap<u64, BufReader<File>>) -> Result<BufWriter> {
-        let p = path.join(format!("{}.log", fid));
-        let mut fd = OpenOptions::new()
+    // load the index straight from the snapshot, if one exists, instead of
+    // replaying every log file from scratch on every open.
+    fn load_snapshot(path: &Path) -> Result<(BTreeMap<String, Pointer>, u64)> {
+        let snapshot_path = path.join(SNAPSHOT_FILE);
+        if std::fs::metadata(&snapshot_path).is_err() {
+            return Ok((BTreeMap::new(), 0));
+        }
+        let fd = OpenOptions::new().read(true).open(snapshot_path)?;
+        let snapshot: Snapshot = serde_json::from_reader(fd)?;
+        Ok((snapshot.index, snapshot.last_fid))
+    }
+
+    fn new_log_file(fid: u64, path: &Path, readers: &mut HashMap<u64, Reader>) -> Result<BufWriter> {
+        let p = path.join(format!("{}.{}", fid, LOG_EXT));
+        let is_new = !p.exists();
+        let fd = OpenOptions::new()
             .create(true).append(true).open(p.clone())?;
         let mut writer = BufWriter::new(fd);
-        let mut r = OpenOptions::new().read(true).open(p)?;
-        readers.insert(fid, BufReader::new(r));
+        if is_new {
+            Self::write_log_header(&mut writer)?;
+            writer.flush()?;
+        }
+        let r = OpenOptions::new().read(true).write(true).open(p)?;
+        readers.insert(fid, Reader::Plain(BufReader::new(r)));
         Ok(writer)
     }
 
+    // an active log is always uncompressed; a compacted segment is always
+    // named `<fid>.zlog`. Check which one is on disk for this fid.
+    fn segment_path(path: &Path, fid: u64) -> (PathBuf, bool) {
+        let zlog = path.join(format!("{}.{}", fid, COMPRESSED_LOG_EXT));
+        if zlog.exists() {
+            (zlog, true)
+        } else {
+            (path.join(format!("{}.{}", fid, LOG_EXT)), false)
+        }
+    }
+
+    // compressed segments are decompressed fully into memory at open time,
+    // since `get` relies on random-access seeking by byte offset and a
+    // compacted segment is written once but read many times.
+    fn open_reader(path: &Path, compressed: bool) -> Result<Reader> {
+        if compressed {
+            let decoded = zstd::decode_all(OpenOptions::new().read(true).open(path)?)?;
+            Ok(Reader::Compressed(Cursor::new(decoded)))
+        } else {
+            let fd = OpenOptions::new().read(true).write(true).open(path)?;
+            Ok(Reader::Plain(BufReader::new(fd)))
+        }
+    }
+
     pub fn sort_log(path: &Path) -> Result<Vec<u64>> {
         let mut file_ids: Vec<u64> = read_dir(path)?
             .flat_map(|dir| -> Result<_> {
@@ -235,7 +702,10 @@ impl KvStore {
             })
             .filter(|p|
                 // there's a problem, if I use p.ends_with(".log")
-                p.is_file() && p.extension() == Some("log".as_ref())
+                p.is_file() && matches!(
+                    p.extension().and_then(|e| e.to_str()),
+                    Some(ext) if ext == LOG_EXT || ext == COMPRESSED_LOG_EXT
+                )
             )
             .flat_map(|p|
                 p.file_stem()
@@ -245,37 +715,385 @@ impl KvStore {
             .flatten()
             .collect();
         file_ids.sort();
+        file_ids.dedup();
         Ok(file_ids)
     }
 
-    // I don't like JSON for this case because it's very inefficient.
-    // Nobody need watch the log file themselves
-    // But the serde_json crate is convenient, because it can handle the byte stream correctly
-    // without marking some isolation flags.
-    // If we don't use serde_json, we have to store the index in disk,
-    // only by doing that can we rebuild the data
-    fn load_data(fid: u64, r: &mut BufReader<File>, index: &mut HashMap<String, Pointer>) -> Result<u64> {
-        let mut pos = r.seek(SeekFrom::Start(0))?;
-        let mut stream = Deserializer::from_reader(r).into_iter::<Operation>();
+    // frame a single record as [u32 payload_len][u32 crc32(payload)][payload],
+    // all little-endian, so load_data can verify each record before trusting it.
+    fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> Result<()> {
+        let len = payload.len() as u32;
+        let crc = crc32fast::hash(payload);
+        writer.write_all(&len.to_le_bytes())?;
+        writer.write_all(&crc.to_le_bytes())?;
+        writer.write_all(payload)?;
+        Ok(())
+    }
+
+    // a compact binary encoding of `Operation`, little-endian throughout:
+    // a one-byte op tag, then length-prefixed key bytes, then for `Set` the
+    // length-prefixed value bytes. Replaces serde_json on the hot path.
+    fn encode_operation(op: &Operation) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match op {
+            Operation::Set(key, value) => {
+                buf.push(OP_SET);
+                buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+                buf.extend_from_slice(key.as_bytes());
+                buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+                buf.extend_from_slice(value.as_bytes());
+            },
+            Operation::Rm(key) => {
+                buf.push(OP_RM);
+                buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+                buf.extend_from_slice(key.as_bytes());
+            },
+            Operation::BatchBegin(count) => {
+                buf.push(OP_BATCH_BEGIN);
+                buf.extend_from_slice(&count.to_le_bytes());
+            },
+            Operation::BatchEnd => {
+                buf.push(OP_BATCH_END);
+            },
+            Operation::Get(key) => {
+                buf.push(OP_GET);
+                buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+                buf.extend_from_slice(key.as_bytes());
+            },
+        }
+        buf
+    }
+
+    fn decode_operation(bytes: &[u8]) -> Result<Operation> {
+        let mut cursor = Cursor::new(bytes);
+        let mut tag = [0u8; 1];
+        cursor.read_exact(&mut tag)?;
+        match tag[0] {
+            OP_SET => {
+                let key = Self::read_lenpfx_string(&mut cursor)?;
+                let value = Self::read_lenpfx_string(&mut cursor)?;
+                Ok(Operation::Set(key, value))
+            },
+            OP_RM => {
+                let key = Self::read_lenpfx_string(&mut cursor)?;
+                Ok(Operation::Rm(key))
+            },
+            OP_BATCH_BEGIN => {
+                let mut count_buf = [0u8; 4];
+                cursor.read_exact(&mut count_buf)?;
+                Ok(Operation::BatchBegin(u32::from_le_bytes(count_buf)))
+            },
+            OP_BATCH_END => Ok(Operation::BatchEnd),
+            OP_GET => {
+                let key = Self::read_lenpfx_string(&mut cursor)?;
+                Ok(Operation::Get(key))
+            },
+            _ => Err(err::KvError::UnKnownCommand),
+        }
+    }
+
+    fn read_lenpfx_string<R: Read>(r: &mut R) -> Result<String> {
+        let mut len_buf = [0u8; 4];
+        r.read_exact(&mut len_buf)?;
+        let len = u32::from_le_bytes(len_buf) as usize;
+        let mut buf = vec![0u8; len];
+        r.read_exact(&mut buf)?;
+        String::from_utf8(buf).map_err(|_| err::KvError::UnKnownCommand)
+    }
+
+    // write the one-byte format-version header every log file starts with.
+    fn write_log_header<W: Write>(writer: &mut W) -> Result<()> {
+        writer.write_all(&[LOG_FORMAT_VERSION])?;
+        Ok(())
+    }
+
+    // verify the format-version header every log file starts with, so an
+    // older (e.g. plain-JSON) log is rejected with a clear error instead of
+    // being silently misparsed.
+    fn read_log_header<R: Read>(r: &mut R) -> Result<()> {
+        let mut version = [0u8; 1];
+        r.read_exact(&mut version)?;
+        if version[0] != LOG_FORMAT_VERSION {
+            return Err(err::KvError::UnsupportedLogVersion(version[0]));
+        }
+        Ok(())
+    }
+
+    // Every record is framed with a length + crc32 header (see write_frame). A
+    // header that runs past EOF, a payload that runs past EOF, or a crc
+    // mismatch all mean the record was torn by a crash mid-write: replay stops
+    // there and the file is truncated back to the last fully verified record,
+    // so a partial append never corrupts the index or aborts startup.
+    fn load_data<R: Read + Seek + AsFile>(fid: u64, r: &mut R, index: &mut BTreeMap<String, Pointer>) -> Result<u64> {
+        r.seek(SeekFrom::Start(0))?;
+        Self::read_log_header(r)?;
+        let mut pos = 1u64;
         let mut acc = 0;
-        while let Some(v) = stream.next() {
-            let cursor =  stream.byte_offset();
-            let op = v?;
+        // while Some, we're inside a WriteBatch (see `PendingBatch`).
+        let mut batch: Option<PendingBatch> = None;
+        loop {
+            let mut header = [0u8; HEADER_LEN as usize];
+            if let Err(e) = r.read_exact(&mut header) {
+                if e.kind() == ErrorKind::UnexpectedEof {
+                    break;
+                }
+                return Err(e.into());
+            }
+            let payload_len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+            let expected_crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+
+            let mut payload = vec![0u8; payload_len];
+            if r.read_exact(&mut payload).is_err() {
+                break;
+            }
+            if crc32fast::hash(&payload) != expected_crc {
+                break;
+            }
+
+            let op = Self::decode_operation(&payload)?;
+            let payload_start = pos + HEADER_LEN;
             match op {
-                Operation::Set(key, _) => {
-                    if let Some(old) = index.insert(key, Pointer::new(fid, pos, cursor as u64-pos)) {
-                        acc += old.len;
+                Operation::BatchBegin(count) => {
+                    batch = Some((pos, count, Vec::new()));
+                },
+                Operation::BatchEnd => {
+                    if let Some((_, expected_count, ops)) = batch.take() {
+                        // a BatchEnd whose enclosed record count doesn't match
+                        // what its BatchBegin declared means the batch is
+                        // corrupt in some way load_data can't otherwise catch
+                        // (e.g. records dropped from the middle but a later,
+                        // still-valid-looking BatchEnd frame survived); don't
+                        // apply any of its ops rather than risk a partial
+                        // batch landing silently.
+                        if ops.len() as u32 == expected_count {
+                            for (pending_op, p_start, p_len) in ops {
+                                acc += Self::apply_op(fid, pending_op, p_start, p_len, index);
+                            }
+                        }
                     }
                 },
-                Operation::Rm(key) => {
-                    if let Some(old) = index.remove(&key) {
-                        acc += old.len;
+                Operation::Set(..) | Operation::Rm(..) => {
+                    if let Some((_, _, ops)) = &mut batch {
+                        ops.push((op, payload_start, payload_len as u64));
+                    } else {
+                        acc += Self::apply_op(fid, op, payload_start, payload_len as u64, index);
                     }
-                    acc += cursor as u64 - pos;
                 },
+                // Get is only ever sent over the wire by KvsClient and never
+                // written to a log, so it can't appear during replay.
+                Operation::Get(_) => unreachable!("Get is never written to a log"),
             }
-            pos = cursor as u64;
+            pos = payload_start + payload_len as u64;
+        }
+
+        // an unterminated batch never happened as far as the index is
+        // concerned; roll the valid length back to where it began so a
+        // crash mid-batch discards the whole batch, not just its tail.
+        if let Some((batch_start, _, _)) = batch {
+            pos = batch_start;
+        }
+
+        // drop any trailing torn write so the file is clean on the next open;
+        // only the active, uncompressed log is ever appended to, so a
+        // compressed/in-memory reader has nothing on disk to truncate.
+        if let Some(f) = r.as_file() {
+            f.set_len(pos)?;
         }
+        r.seek(SeekFrom::Start(pos))?;
         Ok(acc)
     }
-}
\ No newline at end of file
+
+    // apply a single decoded Set/Rm record to the index, returning the
+    // rubbish byte count it contributes. Shared by plain replay and batch
+    // replay (the whole point of a WriteBatch is that both apply it the
+    // same way, just at different points in the scan).
+    fn apply_op(fid: u64, op: Operation, payload_start: u64, payload_len: u64, index: &mut BTreeMap<String, Pointer>) -> u64 {
+        match op {
+            Operation::Set(key, _) => {
+                let mut acc = 0;
+                if let Some(old) = index.insert(key, Pointer::new(fid, payload_start, payload_len)) {
+                    acc += HEADER_LEN + old.len;
+                }
+                acc
+            },
+            Operation::Rm(key) => {
+                let mut acc = 0;
+                if let Some(old) = index.remove(&key) {
+                    acc += HEADER_LEN + old.len;
+                }
+                acc + HEADER_LEN + payload_len
+            },
+            Operation::BatchBegin(_) | Operation::BatchEnd => 0,
+            Operation::Get(_) => unreachable!("Get is never written to a log"),
+        }
+    }
+
+    // a compact binary encoding of `Response`, mirroring `encode_operation`/
+    // `decode_operation`: a one-byte tag, then a length-prefixed string for
+    // the variants that carry one.
+    fn encode_response(resp: &Response) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match resp {
+            Response::Value(value) => {
+                buf.push(RESP_VALUE);
+                buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+                buf.extend_from_slice(value.as_bytes());
+            },
+            Response::NotFound => {
+                buf.push(RESP_NOT_FOUND);
+            },
+            Response::Err(msg) => {
+                buf.push(RESP_ERR);
+                buf.extend_from_slice(&(msg.len() as u32).to_le_bytes());
+                buf.extend_from_slice(msg.as_bytes());
+            },
+        }
+        buf
+    }
+
+    fn decode_response(bytes: &[u8]) -> Result<Response> {
+        let mut cursor = Cursor::new(bytes);
+        let mut tag = [0u8; 1];
+        cursor.read_exact(&mut tag)?;
+        match tag[0] {
+            RESP_VALUE => Ok(Response::Value(Self::read_lenpfx_string(&mut cursor)?)),
+            RESP_NOT_FOUND => Ok(Response::NotFound),
+            RESP_ERR => Ok(Response::Err(Self::read_lenpfx_string(&mut cursor)?)),
+            _ => Err(err::KvError::UnKnownCommand),
+        }
+    }
+}
+
+// lets `load_data` truncate the backing file for a plain on-disk log without
+// caring whether it was called with a `BufReader<File>` or an in-memory
+// `Cursor` (compressed/recovered segments have no file to truncate).
+trait AsFile {
+    fn as_file(&self) -> Option<&File>;
+}
+
+impl AsFile for BufReader<File> {
+    fn as_file(&self) -> Option<&File> {
+        Some(self.get_ref())
+    }
+}
+
+impl AsFile for Cursor<Vec<u8>> {
+    fn as_file(&self) -> Option<&File> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    static SCRATCH_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    // a fresh, empty scratch directory under the system temp dir, unique per
+    // call so parallel tests (and repeat runs) never collide.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let n = SCRATCH_COUNTER.fetch_add(1, Ordering::SeqCst);
+        let dir = std::env::temp_dir().join(format!("kvs-test-{}-{}-{}", std::process::id(), name, n));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn torn_write_is_truncated_and_earlier_records_still_replay() {
+        let dir = scratch_dir("torn-write");
+        {
+            let mut store = KvStore::open(&dir).unwrap();
+            store.set("a".to_owned(), "1".to_owned()).unwrap();
+            store.set("b".to_owned(), "2".to_owned()).unwrap();
+        }
+
+        // chop the last few bytes off the active log, as a crash mid-write
+        // to its last frame would leave behind: the header still claims a
+        // full payload, but the payload itself runs past EOF.
+        let log_path = dir.join("1.log");
+        let full_len = std::fs::metadata(&log_path).unwrap().len();
+        let f = OpenOptions::new().write(true).open(&log_path).unwrap();
+        f.set_len(full_len - 3).unwrap();
+        drop(f);
+
+        let mut store = KvStore::open(&dir).unwrap();
+        assert_eq!(store.get("a".to_owned()).unwrap(), Some("1".to_owned()));
+        // "b" was the torn record; it must not resurface as a corrupt value.
+        assert_eq!(store.get("b".to_owned()).unwrap(), None);
+        // and the store must still be writable afterwards.
+        store.set("c".to_owned(), "3".to_owned()).unwrap();
+        assert_eq!(store.get("c".to_owned()).unwrap(), Some("3".to_owned()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_snapshot_after_compaction_still_returns_committed_values() {
+        let dir = scratch_dir("missing-snapshot");
+        let mut store = KvStore::open(&dir).unwrap();
+        store.set("keepme".to_owned(), "somevalue".to_owned()).unwrap();
+        store.compact().unwrap();
+        assert_eq!(store.get("keepme".to_owned()).unwrap(), Some("somevalue".to_owned()));
+        drop(store);
+
+        // as if `open` crashed after committing the compacted segment but
+        // before writing the snapshot covering it.
+        std::fs::remove_file(dir.join(SNAPSHOT_FILE)).unwrap();
+
+        let mut store = KvStore::open(&dir).unwrap();
+        assert_eq!(store.get("keepme".to_owned()).unwrap(), Some("somevalue".to_owned()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unterminated_batch_is_discarded_but_earlier_committed_batch_survives() {
+        let dir = scratch_dir("batch-durability");
+        {
+            let mut store = KvStore::open(&dir).unwrap();
+            let mut committed = WriteBatch::new();
+            committed.set("a".to_owned(), "1".to_owned());
+            committed.set("b".to_owned(), "2".to_owned());
+            store.write(committed).unwrap();
+        }
+
+        // append a BatchBegin and one Set frame directly, with no BatchEnd,
+        // as a crash partway through a second batch would leave behind.
+        let log_path = dir.join("1.log");
+        let mut f = OpenOptions::new().append(true).open(&log_path).unwrap();
+        let begin = KvStore::encode_operation(&Operation::BatchBegin(1));
+        KvStore::write_frame(&mut f, &begin).unwrap();
+        let set_c = KvStore::encode_operation(&Operation::Set("c".to_owned(), "3".to_owned()));
+        KvStore::write_frame(&mut f, &set_c).unwrap();
+        drop(f);
+
+        let mut store = KvStore::open(&dir).unwrap();
+        assert_eq!(store.get("a".to_owned()).unwrap(), Some("1".to_owned()));
+        assert_eq!(store.get("b".to_owned()).unwrap(), Some("2".to_owned()));
+        // the unterminated batch never happened as far as the index is concerned.
+        assert_eq!(store.get("c".to_owned()).unwrap(), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn upgrade_migrates_legacy_json_log_to_current_format() {
+        let dir = scratch_dir("legacy-upgrade");
+        // a pre-chunk0-5 directory: a plain log of back-to-back serde_json
+        // `LegacyOperation` values, no length/crc framing, no version byte.
+        std::fs::write(
+            dir.join("1.log"),
+            br#"{"Set":["a","1"]}{"Set":["b","2"]}{"Rm":"b"}"#,
+        ).unwrap();
+
+        KvStore::upgrade(&dir).unwrap();
+
+        let mut store = KvStore::open(&dir).unwrap();
+        assert_eq!(store.get("a".to_owned()).unwrap(), Some("1".to_owned()));
+        assert_eq!(store.get("b".to_owned()).unwrap(), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -4,42 +4,77 @@ use clap::App;
 use std::process::exit;
 use kvs::{KvStore, Result};
 use kvs::err::KvError;
+use kvs::server::KvsServer;
+use kvs::client::KvsClient;
 use std::env;
-use kvs::err::KvError::KeyNotFound;
 
 
 fn main() -> Result<()> {
     let yaml = load_yaml!("cli.yml");
-    let mut cfg = App::from_yaml(yaml).version(env!("CARGO_PKG_VERSION"));
+    let cfg = App::from_yaml(yaml).version(env!("CARGO_PKG_VERSION"));
     let matches = cfg.get_matches();
     let dir = env::current_dir()?;
     match matches.subcommand() {
         ("set", Some(set_matches)) => {
             let key = set_matches.value_of("KEY").expect("key is required");
             let value = set_matches.value_of("VALUE").expect("value is required");
-            let mut store = KvStore::open(dir)?;
-            if let Err(e) = store.set(key.to_owned(), value.to_owned()) {
-                println!("{:?}", e);
+            if let Some(addr) = set_matches.value_of("addr") {
+                let mut client = KvsClient::connect(addr)?;
+                if let Err(e) = client.set(key.to_owned(), value.to_owned()) {
+                    println!("{:?}", e);
+                }
+            } else {
+                let mut store = KvStore::open(dir)?;
+                if let Err(e) = store.set(key.to_owned(), value.to_owned()) {
+                    println!("{:?}", e);
+                }
             }
         },
         ("get", Some(get_matches)) => {
             let key = get_matches.value_of("KEY").expect("key is required");
-            let mut store = KvStore::open(dir)?;
-            let value = store.get(key.to_owned())?.unwrap_or("Key not found".to_owned());
-            println!("{}", value);
+            if let Some(addr) = get_matches.value_of("addr") {
+                let mut client = KvsClient::connect(addr)?;
+                let value = client.get(key.to_owned())?.unwrap_or("Key not found".to_owned());
+                println!("{}", value);
+            } else {
+                let mut store = KvStore::open(dir)?;
+                let value = store.get(key.to_owned())?.unwrap_or("Key not found".to_owned());
+                println!("{}", value);
+            }
         },
         ("rm", Some(rm_mathces)) => {
             let key = rm_mathces.value_of("KEY").expect("key is required");
-            let mut store = KvStore::open(dir)?;
-            if let Err(e) = store.remove(key.to_owned()) {
-                if matches!(e, KvError::KeyNotFound) {
-                    println!("Key not found");
-                    exit(1);
-                } else {
-                    println!("{:?}", e);
+            if let Some(addr) = rm_mathces.value_of("addr") {
+                let mut client = KvsClient::connect(addr)?;
+                if let Err(e) = client.remove(key.to_owned()) {
+                    if matches!(e, KvError::KeyNotFound) {
+                        println!("Key not found");
+                        exit(1);
+                    } else {
+                        println!("{:?}", e);
+                    }
+                }
+            } else {
+                let mut store = KvStore::open(dir)?;
+                if let Err(e) = store.remove(key.to_owned()) {
+                    if matches!(e, KvError::KeyNotFound) {
+                        println!("Key not found");
+                        exit(1);
+                    } else {
+                        println!("{:?}", e);
+                    }
                 }
             }
         },
+        ("serve", Some(serve_matches)) => {
+            let addr = serve_matches.value_of("addr").unwrap_or("127.0.0.1:4000");
+            let store = KvStore::open(dir)?;
+            let mut server = KvsServer::new(store);
+            server.run(addr)?;
+        },
+        ("upgrade", Some(_)) => {
+            KvStore::upgrade(dir)?;
+        },
         ("", None) => {
             eprintln!("unimplemented");
             exit(1);
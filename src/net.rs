@@ -0,0 +1,23 @@
+// length-prefixed framing used by `KvsServer`/`KvsClient` to exchange
+// `Operation`/`Response` payloads over a `TcpStream`. No CRC here: unlike the
+// on-disk log, a TCP stream is not expected to survive a crash mid-write, and
+// a dropped connection just fails the in-flight request.
+use crate::err::Result;
+use std::io::prelude::*;
+
+pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> Result<()> {
+    let len = payload.len() as u32;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn read_frame<R: Read>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut len_buf = [0u8; 4];
+    reader.read_exact(&mut len_buf)?;
+    let len = u32::from_le_bytes(len_buf) as usize;
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    Ok(payload)
+}
@@ -0,0 +1,53 @@
+// Opens a `KvStore` once and serves it over TCP, so the in-memory index
+// survives across requests instead of being rebuilt by every CLI invocation.
+use crate::net::{read_frame, write_frame};
+use crate::{KvStore, Operation, Response};
+use crate::err::Result;
+use std::net::{TcpListener, ToSocketAddrs};
+
+pub struct KvsServer {
+    store: KvStore,
+}
+
+impl KvsServer {
+    pub fn new(store: KvStore) -> Self {
+        Self { store }
+    }
+
+    pub fn run(&mut self, addr: impl ToSocketAddrs) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        for stream in listener.incoming() {
+            let mut stream = stream?;
+            while let Ok(payload) = read_frame(&mut stream) {
+                let resp = match KvStore::decode_operation(&payload) {
+                    Ok(op) => self.dispatch(op),
+                    Err(e) => Response::Err(e.to_string()),
+                };
+                write_frame(&mut stream, &KvStore::encode_response(&resp))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn dispatch(&mut self, op: Operation) -> Response {
+        match op {
+            Operation::Get(key) => match self.store.get(key) {
+                Ok(Some(value)) => Response::Value(value),
+                Ok(None) => Response::NotFound,
+                Err(e) => Response::Err(e.to_string()),
+            },
+            Operation::Set(key, value) => match self.store.set(key, value) {
+                Ok(()) => Response::Value(String::new()),
+                Err(e) => Response::Err(e.to_string()),
+            },
+            Operation::Rm(key) => match self.store.remove(key) {
+                Ok(()) => Response::Value(String::new()),
+                Err(crate::err::KvError::KeyNotFound) => Response::NotFound,
+                Err(e) => Response::Err(e.to_string()),
+            },
+            Operation::BatchBegin(_) | Operation::BatchEnd => {
+                Response::Err("batches are not supported over the wire".to_owned())
+            },
+        }
+    }
+}